@@ -0,0 +1,126 @@
+//! Portable backend for platforms without `clock_nanosleep` (macOS, iOS,
+//! OpenBSD, DragonFly BSD, WASI, and FreeBSD before 12.0).
+//!
+//! `clock_nanosleep` sleeps to an absolute deadline in one syscall; here we
+//! have to rebuild that out of a relative `nanosleep` plus re-reading the
+//! clock. The deadline is always the absolute `target_time` `Snooze` asks
+//! this clock to sleep until; each pass through the loop re-reads `now` and
+//! sleeps for `target_time - now`, so drift from repeated "now + duration"
+//! arithmetic can't accumulate.
+
+use libc::{CLOCK_MONOTONIC, CLOCK_REALTIME, c_int, c_long, time_t, timespec};
+use std::io;
+use std::ptr::null_mut;
+
+use super::{Clock, SnoozeError, SnoozeResult, Timespec};
+
+mod ffi {
+  use libc::{c_int, timespec};
+
+  extern "C" {
+    pub fn clock_gettime(clock: c_int, tp: *mut timespec) -> c_int;
+    pub fn nanosleep(req: *const timespec, rem: *mut timespec) -> c_int;
+  }
+}
+
+/// Identifies which clock a `Snooze` measures against.
+///
+/// This platform has no `CLOCK_BOOTTIME`. On Darwin (macOS, iOS),
+/// `CLOCK_MONOTONIC` unusually keeps running across suspend, so `Boottime`
+/// can alias to it safely there. The BSDs this backend also covers
+/// (OpenBSD, DragonFly, FreeBSD before 12.0) don't have that property and
+/// have no suspend-inclusive clock to alias to instead, so on those
+/// targets `Boottime` is rejected with `SnoozeError::Unsupported` rather
+/// than silently building a heartbeat that stops advancing across suspend.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockId {
+  Monotonic,
+  Boottime,
+  Realtime
+}
+
+impl ClockId {
+  #[cfg(any(target_os = "macos", target_os = "ios"))]
+  fn as_raw(&self) -> Option<c_int> {
+    match *self {
+      ClockId::Monotonic => Some(CLOCK_MONOTONIC),
+      ClockId::Boottime => Some(CLOCK_MONOTONIC),
+      ClockId::Realtime => Some(CLOCK_REALTIME)
+    }
+  }
+
+  #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+  fn as_raw(&self) -> Option<c_int> {
+    match *self {
+      ClockId::Monotonic => Some(CLOCK_MONOTONIC),
+      ClockId::Boottime => None,
+      ClockId::Realtime => Some(CLOCK_REALTIME)
+    }
+  }
+}
+
+fn clock_gettime(clock: ClockId) -> SnoozeResult<Timespec> {
+  let raw = match clock.as_raw() {
+    Some(raw) => raw,
+    None => return Err(SnoozeError::Unsupported(format!("{:?} is not supported", clock)))
+  };
+  let mut tp = timespec { tv_sec: 0, tv_nsec: 0 };
+  let ret = unsafe {
+    ffi::clock_gettime(raw, &mut tp)
+  };
+  if ret != 0 {
+    let error = io::Error::last_os_error();
+    Err(match error.kind() {
+      io::ErrorKind::InvalidInput => SnoozeError::Unsupported(format!("{:?} is not supported", clock)),
+      _ => SnoozeError::from_io_error(error)
+    })
+  } else {
+    Ok(Timespec::new(tp.tv_sec as i64, tp.tv_nsec as i64))
+  }
+}
+
+/// Sleeps, in a loop, until `clock_gettime(clock)` reaches `target_time`.
+fn sleep_until(clock: ClockId, target_time: &Timespec) -> SnoozeResult<()> {
+  loop {
+    let now = try!(clock_gettime(clock));
+    let remaining = target_time.sub(&now);
+    if remaining.is_past() {
+      return Ok(());
+    }
+    let req = timespec { tv_sec: remaining.secs as time_t, tv_nsec: remaining.nanos as c_long };
+    let ret = unsafe {
+      ffi::nanosleep(&req, null_mut())
+    };
+    if ret != 0 {
+      let error = io::Error::last_os_error();
+      if error.kind() != io::ErrorKind::Interrupted {
+        return Err(SnoozeError::from_io_error(error));
+      }
+    }
+  }
+}
+
+/// The real, system-backed `Clock` for platforms without `clock_nanosleep`.
+pub struct SystemClock {
+  clock: ClockId
+}
+
+impl SystemClock {
+  pub fn new(clock: ClockId) -> SystemClock {
+    SystemClock { clock: clock }
+  }
+  /// This backend never has a fallback to degrade to: the relative spin
+  /// loop *is* its only implementation, so it's always "in use".
+  pub fn is_using_fallback(&self) -> bool {
+    true
+  }
+}
+
+impl Clock for SystemClock {
+  fn now(&self) -> SnoozeResult<Timespec> {
+    clock_gettime(self.clock)
+  }
+  fn sleep_until(&self, target: &Timespec) -> SnoozeResult<()> {
+    sleep_until(self.clock, target)
+  }
+}
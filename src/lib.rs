@@ -0,0 +1,400 @@
+//! Snooze is a small, precise interval timer built on top of the platform's
+//! monotonic clock and absolute-deadline sleep primitives.
+
+extern crate libc;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::duration::Duration;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod linux;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub use linux::{ClockId, SystemClock};
+
+#[cfg(any(all(unix, not(any(target_os = "linux", target_os = "android"))), target_os = "wasi"))]
+mod fallback;
+
+#[cfg(any(all(unix, not(any(target_os = "linux", target_os = "android"))), target_os = "wasi"))]
+pub use fallback::{ClockId, SystemClock};
+
+mod simulated;
+
+pub use simulated::SimulatedClock;
+
+pub type SnoozeResult<T> = Result<T, SnoozeError>;
+
+#[derive(Debug)]
+pub enum SnoozeError {
+  Io(io::Error),
+  Unsupported(String),
+  InvalidDuration(String)
+}
+
+impl SnoozeError {
+  fn from_io_error(error: io::Error) -> SnoozeError {
+    SnoozeError::Io(error)
+  }
+}
+
+impl fmt::Display for SnoozeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      SnoozeError::Io(ref err) => write!(f, "{}", err),
+      SnoozeError::Unsupported(ref msg) => write!(f, "{}", msg),
+      SnoozeError::InvalidDuration(ref msg) => write!(f, "{}", msg)
+    }
+  }
+}
+
+impl Error for SnoozeError {
+  fn description(&self) -> &str {
+    match *self {
+      SnoozeError::Io(ref err) => err.description(),
+      SnoozeError::Unsupported(ref msg) => msg,
+      SnoozeError::InvalidDuration(ref msg) => msg
+    }
+  }
+
+  fn cause(&self) -> Option<&Error> {
+    match *self {
+      SnoozeError::Io(ref err) => Some(err),
+      SnoozeError::Unsupported(_) => None,
+      SnoozeError::InvalidDuration(_) => None
+    }
+  }
+}
+
+impl From<io::Error> for SnoozeError {
+  fn from(error: io::Error) -> SnoozeError {
+    SnoozeError::from_io_error(error)
+  }
+}
+
+/// A point in time or a duration, expressed as 64-bit seconds and
+/// nanoseconds.
+///
+/// This is kept independent of any platform `timespec` so that `Clock`
+/// implementations, and the deadlines `Snooze` computes from them, stay
+/// exact no matter how wide the platform's own time type is.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Timespec {
+  pub secs: i64,
+  pub nanos: i64
+}
+
+impl Timespec {
+  pub fn new(secs: i64, nanos: i64) -> Timespec {
+    Timespec { secs: secs, nanos: nanos }
+  }
+
+  /// Adds two `Timespec`s, carrying `nanos` into `secs` so the result
+  /// always has `nanos` in `0..1_000_000_000`. This also normalizes
+  /// `other` defensively: a hand-constructed `Timespec` with `nanos`
+  /// already outside that range (or negative) can't push the result out
+  /// of it either.
+  fn add(&self, other: &Timespec) -> Timespec {
+    let mut secs = self.secs + other.secs;
+    let mut nanos = self.nanos + other.nanos;
+    const NANOS_IN_SECOND: i64 = 1_000_000_000;
+    while nanos >= NANOS_IN_SECOND {
+      secs += 1;
+      nanos -= NANOS_IN_SECOND;
+    }
+    while nanos < 0 {
+      secs -= 1;
+      nanos += NANOS_IN_SECOND;
+    }
+    Timespec { secs: secs, nanos: nanos }
+  }
+
+  /// Subtracts two `Timespec`s, borrowing from `secs` if `nanos` goes
+  /// negative, so the result's `nanos` is always in `0..1_000_000_000`.
+  fn sub(&self, other: &Timespec) -> Timespec {
+    let mut secs = self.secs - other.secs;
+    let mut nanos = self.nanos - other.nanos;
+    if nanos < 0 {
+      secs -= 1;
+      nanos += 1_000_000_000;
+    }
+    Timespec { secs: secs, nanos: nanos }
+  }
+
+  /// Whether this `Timespec` is already due: zero or negative. Used by
+  /// the platform backends' spin-sleep loops to ask "has the deadline
+  /// arrived, can I stop sleeping" — a different question from
+  /// `target_elapsed`'s "was a period actually missed", which needs a
+  /// strict comparison instead.
+  fn is_past(&self) -> bool {
+    self.secs < 0 || (self.secs == 0 && self.nanos <= 0)
+  }
+}
+
+/// Whether a full period was missed: `target` is strictly before `now`,
+/// not merely due. This is a different question from "has the deadline
+/// arrived, can I stop sleeping" (answered by `Timespec::is_past`, which
+/// is inclusive of the exact-match case) — a tick firing exactly on
+/// schedule must not count as an overrun.
+fn target_elapsed(target: &Timespec, now: &Timespec) -> bool {
+  target.secs < now.secs || (target.secs == now.secs && target.nanos < now.nanos)
+}
+
+/// Validates and normalizes a `Duration` into a `Timespec` with `nanos` in
+/// `0..1_000_000_000`.
+///
+/// `Duration::num_nanoseconds()` returns `None` for very large durations,
+/// and a negative `Duration` would otherwise silently produce a
+/// nonsensical `timespec` once handed to a platform clock.
+fn validate_duration(duration: Duration) -> SnoozeResult<Timespec> {
+  if duration < Duration::zero() {
+    return Err(SnoozeError::InvalidDuration("duration must not be negative".to_string()));
+  }
+  // Check the full duration for overflow up front: once `secs` is split
+  // out below, the sub-second remainder can never overflow on its own,
+  // so checking only the remainder would never actually catch anything.
+  if duration.num_nanoseconds().is_none() {
+    return Err(SnoozeError::InvalidDuration(
+      "duration is too large to represent in nanoseconds".to_string()));
+  }
+  let secs = duration.num_seconds();
+  let nanos = (duration - Duration::seconds(secs)).num_nanoseconds().unwrap_or(0);
+  Ok(Timespec::new(secs, nanos))
+}
+
+/// Abstracts the passage of time so that `Snooze` can be driven by
+/// something other than the real system clock.
+///
+/// `SystemClock` is the default, real implementation, backed by the
+/// platform clock named by a `ClockId` (monotonic for `Snooze::new`).
+/// `SimulatedClock`
+/// lets tests (in this crate or downstream) advance time programmatically
+/// instead of actually sleeping.
+pub trait Clock {
+  fn now(&self) -> SnoozeResult<Timespec>;
+  fn sleep_until(&self, target: &Timespec) -> SnoozeResult<()>;
+}
+
+/// How `Snooze` should react when a tick's consumer takes longer than
+/// `duration`, so the next deadline has already passed before `wait` even
+/// looks at the clock.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OverrunPolicy {
+  /// Advance `last_time` by exactly one `duration`, regardless of how far
+  /// behind that leaves it. This is the original behavior: an overrun
+  /// makes the next several `wait`s return immediately, one per missed
+  /// period, until the schedule catches up.
+  Burst,
+  /// Jump `last_time` forward to the next multiple of `duration` that is
+  /// still in the future, dropping any ticks that were missed instead of
+  /// bursting through them.
+  Skip,
+  /// Behaves like `Burst`, but detects the overrun and reports how many
+  /// periods were missed instead of silently bursting through them unseen.
+  Count
+}
+
+impl Default for OverrunPolicy {
+  fn default() -> OverrunPolicy {
+    OverrunPolicy::Burst
+  }
+}
+
+#[allow(missing_copy_implementations)]
+pub struct Snooze<C: Clock = SystemClock> {
+  clock: C,
+  duration: Timespec,
+  last_time: Timespec,
+  overrun_policy: OverrunPolicy
+}
+
+impl Snooze<SystemClock> {
+  pub fn new(duration: Duration) -> SnoozeResult<Snooze<SystemClock>> {
+    Snooze::with_clock(duration, ClockId::Monotonic)
+  }
+  pub fn with_clock(duration: Duration, clock: ClockId) -> SnoozeResult<Snooze<SystemClock>> {
+    Snooze::with_custom_clock(duration, SystemClock::new(clock))
+  }
+  pub fn is_using_fallback(&self) -> bool {
+    self.clock.is_using_fallback()
+  }
+}
+
+impl<C: Clock> Snooze<C> {
+  /// Builds a `Snooze` driven by an arbitrary `Clock`, e.g. a
+  /// `SimulatedClock` in a test.
+  pub fn with_custom_clock(duration: Duration, clock: C) -> SnoozeResult<Snooze<C>> {
+    let duration = try!(validate_duration(duration));
+    let last_time = try!(clock.now());
+    Ok(Snooze {
+      clock: clock,
+      duration: duration,
+      last_time: last_time,
+      overrun_policy: OverrunPolicy::default()
+    })
+  }
+  pub fn reset(&mut self) -> SnoozeResult<()> {
+    self.last_time = try!(self.clock.now());
+    Ok(())
+  }
+  /// The `Clock` driving this `Snooze`, e.g. to inspect a `SimulatedClock`
+  /// in a test.
+  pub fn clock(&self) -> &C {
+    &self.clock
+  }
+  pub fn overrun_policy(&self) -> OverrunPolicy {
+    self.overrun_policy
+  }
+  pub fn set_overrun_policy(&mut self, policy: OverrunPolicy) {
+    self.overrun_policy = policy;
+  }
+  /// Convenience wrapper around `wait_overrun` for callers that don't care
+  /// how many periods, if any, were missed.
+  pub fn wait(&mut self) -> SnoozeResult<()> {
+    try!(self.wait_overrun());
+    Ok(())
+  }
+  /// Sleeps until the next deadline, applying `overrun_policy` if that
+  /// deadline has already passed, and returns the number of periods that
+  /// were missed (always `0` under `OverrunPolicy::Burst`, which doesn't
+  /// bother detecting overruns it isn't going to act on).
+  pub fn wait_overrun(&mut self) -> SnoozeResult<u64> {
+    let mut target_time = self.last_time.add(&self.duration);
+
+    let overrun = if self.overrun_policy == OverrunPolicy::Burst {
+      0
+    } else {
+      let now = try!(self.clock.now());
+      let mut periods = 0u64;
+      while target_elapsed(&target_time, &now) {
+        target_time = target_time.add(&self.duration);
+        periods += 1;
+      }
+      if self.overrun_policy == OverrunPolicy::Count {
+        // Report how far behind we are without skipping the missed ticks:
+        // fire immediately on the original deadline, same as `Burst`.
+        target_time = self.last_time.add(&self.duration);
+      }
+      periods
+    };
+
+    try!(self.clock.sleep_until(&target_time));
+    self.last_time = target_time;
+    Ok(overrun)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn snooze_with_policy(policy: OverrunPolicy) -> Snooze<SimulatedClock> {
+    let clock = SimulatedClock::new(Timespec::new(0, 0));
+    let mut snooze = Snooze::with_custom_clock(Duration::milliseconds(100), clock).unwrap();
+    snooze.set_overrun_policy(policy);
+    snooze
+  }
+
+  #[test]
+  fn wait_produces_exactly_spaced_deadlines_with_no_drift() {
+    let mut snooze = snooze_with_policy(OverrunPolicy::Burst);
+    for _ in 0..3 {
+      snooze.wait().unwrap();
+    }
+    assert_eq!(snooze.clock().requested_deadlines(), vec![
+      Timespec::new(0, 100_000_000),
+      Timespec::new(0, 200_000_000),
+      Timespec::new(0, 300_000_000)
+    ]);
+  }
+
+  #[test]
+  fn burst_keeps_firing_at_the_original_cadence_after_an_overrun() {
+    let mut snooze = snooze_with_policy(OverrunPolicy::Burst);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    // The consumer's work takes 2.5 periods; nothing skips, so the
+    // deadlines stay 100ms apart and `wait` just returns immediately
+    // until the schedule catches up.
+    snooze.clock().advance(0, 250_000_000);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    assert_eq!(snooze.clock().requested_deadlines(), vec![
+      Timespec::new(0, 100_000_000),
+      Timespec::new(0, 200_000_000),
+      Timespec::new(0, 300_000_000),
+      Timespec::new(0, 400_000_000)
+    ]);
+  }
+
+  #[test]
+  fn skip_jumps_to_the_next_future_deadline_and_reports_the_overrun() {
+    let mut snooze = snooze_with_policy(OverrunPolicy::Skip);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    snooze.clock().advance(0, 250_000_000);
+    assert_eq!(snooze.wait_overrun().unwrap(), 2);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    assert_eq!(snooze.clock().requested_deadlines(), vec![
+      Timespec::new(0, 100_000_000),
+      Timespec::new(0, 400_000_000),
+      Timespec::new(0, 500_000_000)
+    ]);
+  }
+
+  #[test]
+  fn count_reports_the_overrun_without_skipping_any_deadlines() {
+    let mut snooze = snooze_with_policy(OverrunPolicy::Count);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    snooze.clock().advance(0, 250_000_000);
+    assert_eq!(snooze.wait_overrun().unwrap(), 2);
+    assert_eq!(snooze.wait_overrun().unwrap(), 0);
+
+    assert_eq!(snooze.clock().requested_deadlines(), vec![
+      Timespec::new(0, 100_000_000),
+      Timespec::new(0, 200_000_000),
+      Timespec::new(0, 300_000_000)
+    ]);
+  }
+
+  #[test]
+  fn on_time_tick_is_never_counted_as_an_overrun() {
+    // Regression test: a consumer that takes exactly one period leaves
+    // `now` exactly equal to the next deadline, not past it. That must
+    // not be treated as a missed period under a policy that acts on
+    // overruns.
+    let mut snooze = snooze_with_policy(OverrunPolicy::Skip);
+    for _ in 0..5 {
+      snooze.clock().advance(0, 100_000_000);
+      assert_eq!(snooze.wait_overrun().unwrap(), 0);
+    }
+  }
+
+  #[test]
+  fn validate_duration_rejects_negative_durations() {
+    match validate_duration(Duration::milliseconds(-1)) {
+      Err(SnoozeError::InvalidDuration(_)) => {},
+      other => panic!("expected InvalidDuration, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn validate_duration_rejects_durations_too_large_for_nanoseconds() {
+    // 10^12 seconds, multiplied out to nanoseconds, overflows i64.
+    match validate_duration(Duration::seconds(1_000_000_000_000)) {
+      Err(SnoozeError::InvalidDuration(_)) => {},
+      other => panic!("expected InvalidDuration, got {:?}", other)
+    }
+  }
+
+  #[test]
+  fn validate_duration_normalizes_sub_second_nanos() {
+    let timespec = validate_duration(Duration::milliseconds(1500)).unwrap();
+    assert_eq!(timespec, Timespec::new(1, 500_000_000));
+  }
+}
@@ -1,10 +1,12 @@
-use libc::{CLOCK_MONOTONIC, c_long, time_t, timespec};
+use libc::{CLOCK_BOOTTIME, CLOCK_MONOTONIC, CLOCK_REALTIME, EINTR, EINVAL, ENOTSUP, RTLD_DEFAULT,
+           c_char, c_int, c_long, c_void, dlsym, time_t, timespec};
+use std::cell::Cell;
 use std::io;
 use std::mem;
 use std::ptr::null_mut;
-use std::time::duration::Duration;
+use std::sync::{Once, ONCE_INIT};
 
-use super::{SnoozeError, SnoozeResult};
+use super::{Clock, SnoozeError, SnoozeResult, Timespec};
 
 mod ffi {
   use libc::{c_int, timespec};
@@ -14,76 +16,254 @@ mod ffi {
   extern "C" {
     pub fn clock_gettime(clock: c_int, tp: *mut timespec) -> c_int;
     pub fn clock_nanosleep(clock: c_int, flags: c_int, req: *const timespec, rem: *mut timespec) -> c_int;
+    pub fn nanosleep(req: *const timespec, rem: *mut timespec) -> c_int;
   }
 }
 
-fn clock_gettime() -> SnoozeResult<timespec> {
+/// Identifies which kernel clock a `Snooze` measures and sleeps against.
+///
+/// `Monotonic` does not advance while the system is suspended, so a
+/// `Snooze` built on it will not "catch up" missed ticks after a resume.
+/// `Boottime` includes suspended time, which is usually what a heartbeat
+/// or watchdog wants. `Realtime` tracks the wall clock, so its absolute
+/// deadlines move if the system time is stepped.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ClockId {
+  Monotonic,
+  Boottime,
+  Realtime
+}
+
+impl ClockId {
+  fn as_raw(&self) -> c_int {
+    match *self {
+      ClockId::Monotonic => CLOCK_MONOTONIC,
+      ClockId::Boottime => CLOCK_BOOTTIME,
+      ClockId::Realtime => CLOCK_REALTIME
+    }
+  }
+}
+
+/// 64-bit `timespec`, laid out like glibc's `__timespec64`, for use with
+/// the `*_time64` symbols.
+#[cfg(target_endian = "little")]
+#[repr(C)]
+struct timespec64 {
+  tv_sec: i64,
+  tv_nsec: i32,
+  __padding: i32
+}
+
+#[cfg(target_endian = "big")]
+#[repr(C)]
+struct timespec64 {
+  tv_sec: i64,
+  __padding: i32,
+  tv_nsec: i32
+}
+
+impl timespec64 {
+  fn new(secs: i64, nanos: i64) -> timespec64 {
+    timespec64 { tv_sec: secs, tv_nsec: nanos as i32, __padding: 0 }
+  }
+}
+
+type ClockGettime64Fn = unsafe extern "C" fn(c_int, *mut timespec64) -> c_int;
+type ClockNanosleepTime64Fn = unsafe extern "C" fn(c_int, c_int, *const timespec64, *mut timespec64) -> c_int;
+
+static TIME64_INIT: Once = ONCE_INIT;
+static mut CLOCK_GETTIME64: Option<ClockGettime64Fn> = None;
+static mut CLOCK_NANOSLEEP_TIME64: Option<ClockNanosleepTime64Fn> = None;
+
+/// Resolves `__clock_gettime64`/`__clock_nanosleep_time64` the way a weak
+/// symbol reference would: via `dlsym`, so a binary built against this
+/// code still links and runs on an older glibc that doesn't export them,
+/// and just uses the legacy, 32-bit-`time_t` entry points instead.
+fn time64_fns() -> (Option<ClockGettime64Fn>, Option<ClockNanosleepTime64Fn>) {
+  unsafe {
+    TIME64_INIT.call_once(|| {
+      CLOCK_GETTIME64 = lookup_symbol(b"__clock_gettime64\0")
+        .map(|sym| mem::transmute::<*mut c_void, ClockGettime64Fn>(sym));
+      CLOCK_NANOSLEEP_TIME64 = lookup_symbol(b"__clock_nanosleep_time64\0")
+        .map(|sym| mem::transmute::<*mut c_void, ClockNanosleepTime64Fn>(sym));
+    });
+    (CLOCK_GETTIME64, CLOCK_NANOSLEEP_TIME64)
+  }
+}
+
+fn lookup_symbol(name: &'static [u8]) -> Option<*mut c_void> {
+  let sym = unsafe { dlsym(RTLD_DEFAULT, name.as_ptr() as *const c_char) };
+  if sym.is_null() { None } else { Some(sym) }
+}
+
+/// Converts a `Timespec` to the platform's native `timespec`, failing if
+/// the value doesn't round-trip through `time_t` (only possible on
+/// platforms where `time_t` is narrower than 64 bits, i.e. past 2038 on
+/// 32-bit glibc).
+fn legacy_timespec(time: &Timespec) -> Option<timespec> {
+  let secs = time.secs as time_t;
+  if secs as i64 != time.secs {
+    return None;
+  }
+  Some(timespec { tv_sec: secs, tv_nsec: time.nanos as c_long })
+}
+
+fn translate_clock_error(clock: ClockId) -> SnoozeError {
+  let error = io::Error::last_os_error();
+  match error.kind() {
+    io::ErrorKind::InvalidInput => SnoozeError::Unsupported(format!("{:?} is not supported", clock)),
+    _ => SnoozeError::from_io_error(error)
+  }
+}
+
+fn clock_gettime(clock: ClockId) -> SnoozeResult<Timespec> {
+  let (gettime64, _) = time64_fns();
+  if let Some(gettime64) = gettime64 {
+    let mut tp = timespec64::new(0, 0);
+    let ret = unsafe { gettime64(clock.as_raw(), &mut tp) };
+    return if ret != 0 {
+      Err(translate_clock_error(clock))
+    } else {
+      Ok(Timespec::new(tp.tv_sec, tp.tv_nsec as i64))
+    };
+  }
+
   let mut tp: timespec = unsafe { mem::uninitialized() };
-  let ret = unsafe {
-    ffi::clock_gettime(CLOCK_MONOTONIC, &mut tp)
-  };
+  let ret = unsafe { ffi::clock_gettime(clock.as_raw(), &mut tp) };
   if ret != 0 {
-    let error = io::Error::last_os_error();
-    Err(match error.kind() {
-      io::ErrorKind::InvalidInput => SnoozeError::Unsupported("CLOCK_MONOTONIC is not supported".to_string()),
-      _ => SnoozeError::from_io_error(error)
-    })
-  } else { Ok(tp) }
-}
-
-fn clock_nanosleep(time: &timespec) -> SnoozeResult<()> {
-  while unsafe {
-    ffi::clock_nanosleep(CLOCK_MONOTONIC, ffi::TIMER_ABSTIME, time, null_mut())
-  } != 0 {
-    let error = io::Error::last_os_error();
-    if error.kind() != io::ErrorKind::Interrupted {
-      return Err(SnoozeError::from_io_error(error));
+    Err(translate_clock_error(clock))
+  } else {
+    Ok(Timespec::new(tp.tv_sec as i64, tp.tv_nsec as i64))
+  }
+}
+
+/// Outcome of a single `clock_nanosleep` attempt that didn't just succeed.
+enum NanosleepError {
+  /// The kernel doesn't support absolute sleeps on this clock; the caller
+  /// should degrade to the relative spin loop and remember to keep doing so.
+  Unsupported,
+  Other(SnoozeError)
+}
+
+fn clock_nanosleep(clock: ClockId, target: &Timespec) -> Result<(), NanosleepError> {
+  let (_, nanosleep64) = time64_fns();
+  if let Some(nanosleep64) = nanosleep64 {
+    let req = timespec64::new(target.secs, target.nanos);
+    loop {
+      // Like `clock_nanosleep`, this returns the error number rather than
+      // setting `errno`.
+      match unsafe { nanosleep64(clock.as_raw(), ffi::TIMER_ABSTIME, &req, null_mut()) } {
+        0 => return Ok(()),
+        EINTR => continue,
+        ENOTSUP | EINVAL => return Err(NanosleepError::Unsupported),
+        err => return Err(NanosleepError::Other(SnoozeError::from_io_error(io::Error::from_raw_os_error(err))))
+      }
     }
   }
-  Ok(())
-}
-
-#[allow(missing_copy_implementations)]
-pub struct Snooze {
-  duration: timespec,
-  last_time: timespec
-}
-
-impl Snooze {
-  pub fn new(duration: Duration) -> SnoozeResult<Snooze> {
-    // TODO: Figure out if unwrap() is safe or not
-    let duration_secs = duration.num_seconds();
-    let duration_nanos = (duration - Duration::seconds(duration_secs)).num_nanoseconds().unwrap();
-    Ok(Snooze {
-      duration: timespec {
-        tv_sec: duration_secs as time_t,
-        tv_nsec: duration_nanos as c_long
-      },
-      last_time: try!(clock_gettime())
-    })
-  }
-  pub fn reset(&mut self) -> SnoozeResult<()> {
-    self.last_time = try!(clock_gettime());
-    Ok(())
-  }
-  pub fn wait(&mut self) -> SnoozeResult<()> {
-    let mut seconds =
-      self.last_time.tv_sec + self.duration.tv_sec;
-    let mut nanos =
-      self.last_time.tv_nsec + self.duration.tv_nsec;
-
-    const NANOS_IN_SECOND: c_long = 1000000000;
-    if nanos >= NANOS_IN_SECOND {
-      seconds += 1;
-      nanos -= NANOS_IN_SECOND;
+
+  let legacy_target = match legacy_timespec(target) {
+    Some(time) => time,
+    None => return Err(NanosleepError::Other(SnoozeError::Unsupported(
+      "deadline does not fit in a 32-bit time_t and __clock_nanosleep_time64 is unavailable".to_string())))
+  };
+  loop {
+    // `clock_nanosleep` returns the error number; it does not set `errno`.
+    match unsafe { ffi::clock_nanosleep(clock.as_raw(), ffi::TIMER_ABSTIME, &legacy_target, null_mut()) } {
+      0 => return Ok(()),
+      EINTR => continue,
+      ENOTSUP | EINVAL => return Err(NanosleepError::Unsupported),
+      err => return Err(NanosleepError::Other(SnoozeError::from_io_error(io::Error::from_raw_os_error(err))))
     }
+  }
+}
 
-    let target_time = timespec {
-      tv_sec: seconds,
-      tv_nsec: nanos
+/// Degraded path for clocks where `clock_nanosleep` returns `ENOTSUP`:
+/// sleeps toward the same absolute `target_time` by repeatedly measuring
+/// the remaining interval with `clock_gettime` and sleeping it with the
+/// relative `nanosleep`, so the fixed deadline is still honoured.
+fn clock_nanosleep_fallback(clock: ClockId, target_time: &Timespec) -> SnoozeResult<()> {
+  loop {
+    let now = try!(clock_gettime(clock));
+    let remaining = target_time.sub(&now);
+    if remaining.is_past() {
+      return Ok(());
+    }
+    let req = match legacy_timespec(&remaining) {
+      Some(time) => time,
+      None => return Err(SnoozeError::Unsupported(
+        "remaining sleep interval does not fit in a 32-bit time_t".to_string()))
     };
-    try!(clock_nanosleep(&target_time));
-    self.last_time = target_time;
-    Ok(())
+    let ret = unsafe { ffi::nanosleep(&req, null_mut()) };
+    if ret != 0 {
+      let error = io::Error::last_os_error();
+      if error.raw_os_error() != Some(EINTR) {
+        return Err(SnoozeError::from_io_error(error));
+      }
+    }
+  }
+}
+
+/// The real, system-backed `Clock`: reads and sleeps against a kernel
+/// clock via `clock_gettime`/`clock_nanosleep`, degrading per-instance to
+/// a relative spin loop the first time the kernel reports `ENOTSUP`.
+pub struct SystemClock {
+  clock: ClockId,
+  using_fallback: Cell<bool>
+}
+
+impl SystemClock {
+  pub fn new(clock: ClockId) -> SystemClock {
+    SystemClock { clock: clock, using_fallback: Cell::new(false) }
+  }
+  /// Whether this clock has given up on `clock_nanosleep` and degraded to
+  /// the relative spin-sleep fallback. Once set, this stays set: the
+  /// kernel's lack of support for the clock isn't going to change
+  /// mid-process, so there's no point probing it again every tick.
+  pub fn is_using_fallback(&self) -> bool {
+    self.using_fallback.get()
+  }
+}
+
+impl Clock for SystemClock {
+  fn now(&self) -> SnoozeResult<Timespec> {
+    clock_gettime(self.clock)
+  }
+  fn sleep_until(&self, target: &Timespec) -> SnoozeResult<()> {
+    if self.using_fallback.get() {
+      return clock_nanosleep_fallback(self.clock, target);
+    }
+    match clock_nanosleep(self.clock, target) {
+      Ok(()) => Ok(()),
+      Err(NanosleepError::Unsupported) => {
+        self.using_fallback.set(true);
+        clock_nanosleep_fallback(self.clock, target)
+      }
+      Err(NanosleepError::Other(err)) => Err(err)
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn legacy_timespec_round_trips_values_that_fit_in_time_t() {
+    let time = Timespec::new(1_600_000_000, 500);
+    let legacy = legacy_timespec(&time).expect("value fits in time_t");
+    assert_eq!(legacy.tv_sec as i64, time.secs);
+    assert_eq!(legacy.tv_nsec as i64, time.nanos);
+  }
+
+  #[test]
+  fn legacy_timespec_rejects_secs_that_overflow_a_32_bit_time_t() {
+    // Only demonstrable where `time_t` is actually narrower than `i64`;
+    // on a 64-bit `time_t` nothing in range can fail the round-trip, so
+    // there's nothing to assert.
+    if mem::size_of::<time_t>() >= mem::size_of::<i64>() {
+      return;
+    }
+    let time = Timespec::new(1i64 << 40, 0);
+    assert!(legacy_timespec(&time).is_none());
   }
 }
@@ -0,0 +1,50 @@
+//! A `Clock` that only advances when told to, for deterministic tests.
+
+use std::cell::RefCell;
+
+use super::{Clock, SnoozeResult, Timespec};
+
+/// A `Clock` whose "current time" only moves when `advance` is called or
+/// `Snooze` asks it to sleep.
+///
+/// `sleep_until` never actually sleeps: it jumps the clock straight to the
+/// requested deadline and records it, so a test can assert exactly which
+/// deadlines a `Snooze` asked for without any wall-clock flakiness.
+pub struct SimulatedClock {
+  now: RefCell<Timespec>,
+  requested: RefCell<Vec<Timespec>>
+}
+
+impl SimulatedClock {
+  pub fn new(start: Timespec) -> SimulatedClock {
+    SimulatedClock {
+      now: RefCell::new(start),
+      requested: RefCell::new(Vec::new())
+    }
+  }
+
+  /// Moves the simulated clock forward without going through
+  /// `sleep_until`, e.g. to model work taking time between ticks.
+  pub fn advance(&self, secs: i64, nanos: i64) {
+    let mut now = self.now.borrow_mut();
+    *now = now.add(&Timespec::new(secs, nanos));
+  }
+
+  /// The absolute deadlines `Snooze::wait` has asked this clock to sleep
+  /// until, in call order.
+  pub fn requested_deadlines(&self) -> Vec<Timespec> {
+    self.requested.borrow().clone()
+  }
+}
+
+impl Clock for SimulatedClock {
+  fn now(&self) -> SnoozeResult<Timespec> {
+    Ok(*self.now.borrow())
+  }
+
+  fn sleep_until(&self, target: &Timespec) -> SnoozeResult<()> {
+    self.requested.borrow_mut().push(*target);
+    *self.now.borrow_mut() = *target;
+    Ok(())
+  }
+}